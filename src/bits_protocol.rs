@@ -0,0 +1,307 @@
+//! Types shared between `BitsClient` front ends (`in_process` and `local_service`) describing the
+//! arguments and results of each BITS operation.
+//!
+//! The `XyzFailure` enums here are the inner `Err` of the `Result<Result<_, XyzFailure>, Error>`
+//! that every `BitsClient` method returns: they describe why the BITS operation itself failed, as
+//! opposed to `Error` (a communication failure between the client and whatever is actually
+//! talking to BITS).
+//!
+//! `Command` and `Response` are the wire types used by `local_service`: one `Command` is sent per
+//! `BitsClient` call, and the matching `Response` variant carries back exactly the `Result<_,
+//! XyzFailure>` that the in-process front end would have returned directly.
+
+use std::ffi::OsString;
+
+use failure::Fail;
+use serde_derive::{Deserialize, Serialize};
+
+pub use bits::{BitsJobError, BitsJobStatus, BitsProxyUsage};
+pub use comedy::Error as ComedyError;
+pub use guid_win::Guid;
+
+/// The result of successfully starting a job: its id, for future reference.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StartJobSuccess {
+    pub guid: Guid,
+}
+
+/// The status of one file within a (possibly multi-file) job, as read from
+/// `IBackgroundCopyFile::GetProgress`. `BitsJobStatus::progress` already aggregates these byte
+/// and file counts across the whole job; `BitsFileStatus` is for callers that want to show
+/// progress (or figure out which file is stuck) per file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BitsFileStatus {
+    pub url: OsString,
+    pub local_name: OsString,
+    pub bytes_total: Option<u64>,
+    pub bytes_transferred: u64,
+    pub completed: bool,
+}
+
+#[derive(Debug, Fail)]
+pub enum GetFilesFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+/// The full detail behind a job's `Error`/`TransientError` state: BITS's own `BitsJobError`
+/// (which carries the `BitsErrorContext` naming the failing BITS call, the HRESULT, and its
+/// localized description), plus the URL of the file BITS was working on when it happened, if the
+/// error is specific to one file in the job.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BitsJobFileError {
+    pub error: BitsJobError,
+    pub file_url: Option<OsString>,
+}
+
+#[derive(Debug, Fail)]
+pub enum GetJobErrorFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "The job has no error to report")]
+    NoError,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum StartJobFailure {
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+    #[fail(display = "No files were given to download")]
+    NoFiles,
+}
+
+/// Robustness and HTTP knobs for a job, set via `IBackgroundCopyJob::SetMinimumRetryDelay`,
+/// `SetNoProgressTimeout`, `SetNotifyFlags`, and `IBackgroundCopyJobHttpOptions`. Every field left
+/// at its default (`None`/empty) leaves BITS's own default for that setting untouched.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobOptions {
+    /// `SetMinimumRetryDelay`: seconds BITS waits after a transient error before retrying.
+    pub minimum_retry_delay_secs: Option<u32>,
+    /// `SetNoProgressTimeout`: seconds of no progress before a transient error becomes fatal.
+    pub no_progress_timeout_secs: Option<u32>,
+    /// `IBackgroundCopyJobHttpOptions::SetCustomHeaders`, as literal `name: value` header lines.
+    pub http_headers: Vec<(String, String)>,
+    /// `IBackgroundCopyJobHttpOptions::SetSecurityFlags`' redirect policy: `Some(false)` refuses
+    /// to follow redirects to a different scheme or server than the original URL.
+    pub follow_redirects: Option<bool>,
+    /// `SetNotifyFlags`: the raw `BG_NOTIFY_*` bitmask controlling which job events BITS raises
+    /// (e.g. to `BG_NOTIFY_DISABLE` the job's own completion popup). Left to the caller to
+    /// construct, since the underlying flags are a Windows API detail this crate doesn't mirror.
+    pub notify_flags: Option<u32>,
+}
+
+#[derive(Debug, Fail)]
+pub enum SetJobOptionsFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum MonitorJobFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum SuspendJobFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum ResumeJobFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum SetJobPriorityFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum SetUpdateIntervalFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum CompleteJobFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+#[derive(Debug, Fail)]
+pub enum CancelJobFailure {
+    #[fail(display = "No job with that id was found")]
+    NotFound,
+    #[fail(display = "Windows API error")]
+    Api(#[fail(cause)] ComedyError),
+}
+
+macro_rules! from_comedy {
+    ($($failure:ident),* $(,)?) => {
+        $(
+            impl ::std::convert::From<ComedyError> for $failure {
+                fn from(err: ComedyError) -> $failure {
+                    $failure::Api(err)
+                }
+            }
+        )*
+    };
+}
+
+from_comedy![
+    StartJobFailure,
+    MonitorJobFailure,
+    SuspendJobFailure,
+    ResumeJobFailure,
+    SetJobPriorityFailure,
+    SetUpdateIntervalFailure,
+    CompleteJobFailure,
+    CancelJobFailure,
+    GetFilesFailure,
+    GetJobErrorFailure,
+    SetJobOptionsFailure,
+];
+
+/// A serializable stand-in for a `ComedyError` crossing the Local Service pipe: the HRESULT and
+/// the message `comedy` would have formatted for it, since `ComedyError` itself borrows OS
+/// resources and can't be serialized directly.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HResultMessage {
+    pub hresult: i32,
+    pub message: String,
+}
+
+impl<'a> From<&'a ComedyError> for HResultMessage {
+    fn from(err: &'a ComedyError) -> HResultMessage {
+        HResultMessage {
+            hresult: err.hresult(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<HResultMessage> for ComedyError {
+    /// Reconstructs an error carrying the original HRESULT; the message is not preserved (it's
+    /// only needed for display, and `ComedyError`'s own `Display` already formats the HRESULT).
+    fn from(msg: HResultMessage) -> ComedyError {
+        ComedyError::from_hresult(msg.hresult)
+    }
+}
+
+/// The wire equivalent of the `NotFound`/`Api` shape shared by every `XyzFailure` except
+/// `StartJobFailure` and `GetJobErrorFailure`. `local_service` translates to and from the in-process failure types at the
+/// pipe boundary.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CommandFailure {
+    NotFound,
+    Api(HResultMessage),
+}
+
+/// The wire equivalent of `StartJobFailure`, which has no `NotFound` case.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum StartJobCommandFailure {
+    Api(HResultMessage),
+}
+
+/// The wire equivalent of `GetJobErrorFailure`, which has an extra `NoError` case `CommandFailure`
+/// doesn't model.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum GetJobErrorCommandFailure {
+    NotFound,
+    NoError,
+    Api(HResultMessage),
+}
+
+/// One `Command` is sent per `BitsClient`/`BitsMonitorClient` call made through
+/// `local_service::LocalServiceClient`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Command {
+    StartJob {
+        files: Vec<(OsString, OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    },
+    MonitorJob {
+        guid: Guid,
+        interval_millis: u32,
+    },
+    SuspendJob {
+        guid: Guid,
+    },
+    ResumeJob {
+        guid: Guid,
+    },
+    SetJobPriority {
+        guid: Guid,
+        foreground: bool,
+    },
+    SetUpdateInterval {
+        guid: Guid,
+        interval_millis: u32,
+    },
+    StopUpdate {
+        guid: Guid,
+    },
+    CompleteJob {
+        guid: Guid,
+    },
+    CancelJob {
+        guid: Guid,
+    },
+    GetJobError {
+        guid: Guid,
+    },
+    SetJobOptions {
+        guid: Guid,
+        options: JobOptions,
+    },
+    /// Sent on the monitor pipe to poll for the next status of `guid`.
+    GetStatus {
+        guid: Guid,
+        timeout_millis: u32,
+    },
+    /// Sent on the monitor pipe to read the per-file status of `guid`.
+    GetFiles {
+        guid: Guid,
+    },
+}
+
+/// The `Response` that answers the `Command` of the same name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    StartJob(Result<StartJobSuccess, StartJobCommandFailure>),
+    MonitorJob(Result<(), CommandFailure>),
+    SuspendJob(Result<(), CommandFailure>),
+    ResumeJob(Result<(), CommandFailure>),
+    SetJobPriority(Result<(), CommandFailure>),
+    SetUpdateInterval(Result<(), CommandFailure>),
+    StopUpdate(Result<(), CommandFailure>),
+    CompleteJob(Result<(), CommandFailure>),
+    CancelJob(Result<(), CommandFailure>),
+    GetStatus(Result<BitsJobStatus, CommandFailure>),
+    GetFiles(Result<Vec<BitsFileStatus>, CommandFailure>),
+    GetJobError(Result<BitsJobFileError, GetJobErrorCommandFailure>),
+    SetJobOptions(Result<(), CommandFailure>),
+}
@@ -0,0 +1,403 @@
+//! A `Clone + Send` handle onto a `BitsClient` running on a dedicated worker thread.
+//!
+//! Every `BitsClient` method takes `&mut self`, and the `InProcess` front end wraps COM objects
+//! that are tied to the apartment they were created on, so a `BitsClient` itself has to stay
+//! pinned to one thread -- it can't just be built elsewhere and moved onto the worker thread.
+//! `BitsClientHandle::spawn` instead builds the client *on* the worker thread (from a closure
+//! supplied by the caller) and lets any number of callers, from any thread, submit commands to
+//! it and block for the reply, without building their own thread and channel plumbing to do it.
+
+use std::collections::HashMap;
+use std::ffi;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::bits_protocol::*;
+use crate::{BitsClient, Error, PipeError};
+
+type Reply<T> = mpsc::Sender<T>;
+
+enum Command {
+    StartJob {
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+        reply: Reply<Result<Result<StartJobSuccess, StartJobFailure>, Error>>,
+    },
+    MonitorJob {
+        guid: Guid,
+        interval_millis: u32,
+        reply: Reply<Result<Result<(), MonitorJobFailure>, Error>>,
+    },
+    SuspendJob {
+        guid: Guid,
+        reply: Reply<Result<Result<(), SuspendJobFailure>, Error>>,
+    },
+    ResumeJob {
+        guid: Guid,
+        reply: Reply<Result<Result<(), ResumeJobFailure>, Error>>,
+    },
+    SetJobPriority {
+        guid: Guid,
+        foreground: bool,
+        reply: Reply<Result<Result<(), SetJobPriorityFailure>, Error>>,
+    },
+    SetUpdateInterval {
+        guid: Guid,
+        interval_millis: u32,
+        reply: Reply<Result<Result<(), SetUpdateIntervalFailure>, Error>>,
+    },
+    StopUpdate {
+        guid: Guid,
+        reply: Reply<Result<Result<(), SetUpdateIntervalFailure>, Error>>,
+    },
+    CompleteJob {
+        guid: Guid,
+        reply: Reply<Result<Result<(), CompleteJobFailure>, Error>>,
+    },
+    CancelJob {
+        guid: Guid,
+        reply: Reply<Result<Result<(), CancelJobFailure>, Error>>,
+    },
+    GetJobError {
+        guid: Guid,
+        reply: Reply<Result<Result<BitsJobFileError, GetJobErrorFailure>, Error>>,
+    },
+    SetJobOptions {
+        guid: Guid,
+        options: JobOptions,
+        reply: Reply<Result<Result<(), SetJobOptionsFailure>, Error>>,
+    },
+    GetStatus {
+        guid: Guid,
+        timeout_millis: u32,
+        reply: Reply<Result<BitsJobStatus, Error>>,
+    },
+    GetFiles {
+        guid: Guid,
+        reply: Reply<Result<Vec<BitsFileStatus>, Error>>,
+    },
+}
+
+/// A cloneable, `Send` handle onto a `BitsClient` owned by a worker thread.
+///
+/// Cloning a `BitsClientHandle` is cheap (it's just another sender onto the same command
+/// channel); all clones share the one underlying `BitsClient` and the one set of job monitors.
+/// The worker thread, and the `BitsClient` it owns, are torn down once the last clone, and the
+/// last `BitsMonitorHandle` made from any of them, is dropped.
+#[derive(Clone)]
+pub struct BitsClientHandle {
+    command_tx: mpsc::Sender<Command>,
+}
+
+impl BitsClientHandle {
+    /// Spawn a worker thread, build a `BitsClient` on it by calling `build_client` there, and
+    /// return a handle to it.
+    ///
+    /// `build_client` runs on the new thread rather than this one (e.g. `|| BitsClient::new(...)`
+    /// or `|| BitsClient::new_local_service(...)`) so that the COM objects an `InProcess` client
+    /// wraps are created in, and never leave, the apartment of the thread that uses them.
+    pub fn spawn<F>(build_client: F) -> Result<BitsClientHandle, Error>
+    where
+        F: FnOnce() -> Result<BitsClient, Error> + Send + 'static,
+    {
+        let (command_tx, command_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        thread::spawn(move || match build_client() {
+            Ok(client) => {
+                let _ = ready_tx.send(Ok(()));
+                worker_loop(client, command_rx);
+            }
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+            }
+        });
+
+        recv(ready_rx)?.map(|()| BitsClientHandle { command_tx })
+    }
+
+    pub fn start_job(
+        &self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorHandle), StartJobFailure>, Error> {
+        self.start_job_with_files(vec![(url, save_path)], proxy_usage, monitor_interval_millis)
+    }
+
+    pub fn start_job_with_files(
+        &self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorHandle), StartJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::StartJob {
+            files,
+            proxy_usage,
+            monitor_interval_millis,
+            reply,
+        })?;
+        Ok(recv(rx)?.map(|success| {
+            let monitor = self.monitor_handle(success.guid.clone());
+            (success, monitor)
+        }))
+    }
+
+    pub fn monitor_job(
+        &self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<Result<BitsMonitorHandle, MonitorJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::MonitorJob {
+            guid: guid.clone(),
+            interval_millis,
+            reply,
+        })?;
+        Ok(recv(rx)?.map(|()| self.monitor_handle(guid)))
+    }
+
+    pub fn suspend_job(&self, guid: Guid) -> Result<Result<(), SuspendJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::SuspendJob { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn resume_job(&self, guid: Guid) -> Result<Result<(), ResumeJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::ResumeJob { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn set_job_priority(
+        &self,
+        guid: Guid,
+        foreground: bool,
+    ) -> Result<Result<(), SetJobPriorityFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::SetJobPriority {
+            guid,
+            foreground,
+            reply,
+        })?;
+        recv(rx)
+    }
+
+    pub fn set_update_interval(
+        &self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::SetUpdateInterval {
+            guid,
+            interval_millis,
+            reply,
+        })?;
+        recv(rx)
+    }
+
+    pub fn stop_update(&self, guid: Guid) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::StopUpdate { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn complete_job(&self, guid: Guid) -> Result<Result<(), CompleteJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::CompleteJob { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn cancel_job(&self, guid: Guid) -> Result<Result<(), CancelJobFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::CancelJob { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn get_job_error(
+        &self,
+        guid: Guid,
+    ) -> Result<Result<BitsJobFileError, GetJobErrorFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::GetJobError { guid, reply })?;
+        recv(rx)
+    }
+
+    pub fn set_job_options(
+        &self,
+        guid: Guid,
+        options: JobOptions,
+    ) -> Result<Result<(), SetJobOptionsFailure>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.send(Command::SetJobOptions {
+            guid,
+            options,
+            reply,
+        })?;
+        recv(rx)
+    }
+
+    fn monitor_handle(&self, guid: Guid) -> BitsMonitorHandle {
+        BitsMonitorHandle {
+            guid,
+            command_tx: self.command_tx.clone(),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.command_tx
+            .send(command)
+            .map_err(|_| PipeError::NotConnected)
+    }
+}
+
+/// A cloneable, `Send` handle onto a job's monitor, backed by the same worker thread as the
+/// `BitsClientHandle` that created it.
+///
+/// Only one `BitsMonitorHandle` worth of state is kept per job on the worker thread: starting or
+/// requesting a new monitor for a job (via `BitsClientHandle::start_job`/`monitor_job`) replaces
+/// whatever monitor the worker was previously polling for that job.
+#[derive(Clone)]
+pub struct BitsMonitorHandle {
+    guid: Guid,
+    command_tx: mpsc::Sender<Command>,
+}
+
+impl BitsMonitorHandle {
+    pub fn get_status(&self, timeout_millis: u32) -> Result<BitsJobStatus, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.command_tx
+            .send(Command::GetStatus {
+                guid: self.guid.clone(),
+                timeout_millis,
+                reply,
+            })
+            .map_err(|_| PipeError::NotConnected)?;
+        recv(rx)?
+    }
+
+    pub fn get_files(&self) -> Result<Vec<BitsFileStatus>, Error> {
+        let (reply, rx) = mpsc::channel();
+        self.command_tx
+            .send(Command::GetFiles {
+                guid: self.guid.clone(),
+                reply,
+            })
+            .map_err(|_| PipeError::NotConnected)?;
+        recv(rx)?
+    }
+}
+
+fn recv<T>(rx: mpsc::Receiver<T>) -> Result<T, Error> {
+    rx.recv().map_err(|_| PipeError::NotConnected)
+}
+
+fn worker_loop(mut client: BitsClient, command_rx: mpsc::Receiver<Command>) {
+    let mut monitors = HashMap::new();
+
+    while let Ok(command) = command_rx.recv() {
+        match command {
+            Command::StartJob {
+                files,
+                proxy_usage,
+                monitor_interval_millis,
+                reply,
+            } => {
+                let result = client
+                    .start_job_with_files(files, proxy_usage, monitor_interval_millis)
+                    .map(|result| {
+                        result.map(|(success, monitor)| {
+                            monitors.insert(success.guid.clone(), monitor);
+                            success
+                        })
+                    });
+                let _ = reply.send(result);
+            }
+            Command::MonitorJob {
+                guid,
+                interval_millis,
+                reply,
+            } => {
+                let result = client.monitor_job(guid.clone(), interval_millis).map(|result| {
+                    result.map(|monitor| {
+                        monitors.insert(guid, monitor);
+                    })
+                });
+                let _ = reply.send(result);
+            }
+            Command::SuspendJob { guid, reply } => {
+                let _ = reply.send(client.suspend_job(guid));
+            }
+            Command::ResumeJob { guid, reply } => {
+                let _ = reply.send(client.resume_job(guid));
+            }
+            Command::SetJobPriority {
+                guid,
+                foreground,
+                reply,
+            } => {
+                let _ = reply.send(client.set_job_priority(guid, foreground));
+            }
+            Command::SetUpdateInterval {
+                guid,
+                interval_millis,
+                reply,
+            } => {
+                let _ = reply.send(client.set_update_interval(guid, interval_millis));
+            }
+            Command::StopUpdate { guid, reply } => {
+                let result = client.stop_update(guid.clone());
+                if let Ok(Ok(())) = result {
+                    monitors.remove(&guid);
+                }
+                let _ = reply.send(result);
+            }
+            Command::CompleteJob { guid, reply } => {
+                let result = client.complete_job(guid.clone());
+                if let Ok(Ok(())) = result {
+                    monitors.remove(&guid);
+                }
+                let _ = reply.send(result);
+            }
+            Command::CancelJob { guid, reply } => {
+                let result = client.cancel_job(guid.clone());
+                if let Ok(Ok(())) = result {
+                    monitors.remove(&guid);
+                }
+                let _ = reply.send(result);
+            }
+            Command::GetJobError { guid, reply } => {
+                let _ = reply.send(client.get_job_error(guid));
+            }
+            Command::SetJobOptions {
+                guid,
+                options,
+                reply,
+            } => {
+                let _ = reply.send(client.set_job_options(guid, options));
+            }
+            Command::GetStatus {
+                guid,
+                timeout_millis,
+                reply,
+            } => {
+                let result = match monitors.get_mut(&guid) {
+                    Some(monitor) => monitor.get_status(timeout_millis),
+                    None => Err(PipeError::NotConnected),
+                };
+                let _ = reply.send(result);
+            }
+            Command::GetFiles { guid, reply } => {
+                let result = match monitors.get_mut(&guid) {
+                    Some(monitor) => monitor.get_files(),
+                    None => Err(PipeError::NotConnected),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! The in-process `BitsClient` front end: makes BITS COM calls directly on whatever thread the
+//! caller uses, via the `bits` crate's safe wrappers around `IBackgroundCopyManager` and
+//! `IBackgroundCopyJob`.
+
+use std::ffi;
+use std::path::PathBuf;
+
+use bits::{BackgroundCopyManager, BitsJobStatus, BitsProxyUsage};
+use guid_win::Guid;
+
+use crate::bits_protocol::*;
+
+/// Drives BITS jobs created by this process, all named with a common prefix so that jobs from
+/// other callers are left alone.
+pub struct InProcessClient {
+    job_name: ffi::OsString,
+    save_path_prefix: PathBuf,
+    manager: BackgroundCopyManager,
+}
+
+impl InProcessClient {
+    pub fn new(
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+    ) -> Result<InProcessClient, ComedyError> {
+        Ok(InProcessClient {
+            job_name,
+            save_path_prefix: PathBuf::from(save_path_prefix),
+            manager: BackgroundCopyManager::connect()?,
+        })
+    }
+
+    pub fn start_job(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor), StartJobFailure> {
+        self.start_job_with_files(vec![(url, save_path)], proxy_usage, monitor_interval_millis)
+    }
+
+    /// Start a job downloading every `(url, save_path)` pair in `files` as a single, jointly
+    /// prioritized and resumable BITS job.
+    pub fn start_job_with_files(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor), StartJobFailure> {
+        let mut job = self.manager.create_job(&self.job_name, proxy_usage)?;
+
+        for (url, save_path) in files {
+            job.add_file(&url, &self.save_path_prefix.join(&save_path))?;
+        }
+        job.resume()?;
+
+        let guid = job.guid()?;
+
+        Ok((
+            StartJobSuccess { guid: guid.clone() },
+            InProcessMonitor::new(job, guid, monitor_interval_millis),
+        ))
+    }
+
+    pub fn monitor_job(
+        &mut self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<InProcessMonitor, MonitorJobFailure> {
+        let job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(MonitorJobFailure::NotFound)?;
+
+        Ok(InProcessMonitor::new(job, guid, interval_millis))
+    }
+
+    pub fn suspend_job(&mut self, guid: Guid) -> Result<(), SuspendJobFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(SuspendJobFailure::NotFound)?;
+        job.suspend()?;
+        Ok(())
+    }
+
+    pub fn resume_job(&mut self, guid: Guid) -> Result<(), ResumeJobFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(ResumeJobFailure::NotFound)?;
+        job.resume()?;
+        Ok(())
+    }
+
+    pub fn set_job_priority(
+        &mut self,
+        guid: Guid,
+        foreground: bool,
+    ) -> Result<(), SetJobPriorityFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(SetJobPriorityFailure::NotFound)?;
+        job.set_priority(foreground)?;
+        Ok(())
+    }
+
+    pub fn set_update_interval(
+        &mut self,
+        _guid: Guid,
+        _interval_millis: u32,
+    ) -> Result<(), SetUpdateIntervalFailure> {
+        // The interval is held by each `InProcessMonitor`, not the job itself, and this client
+        // keeps no reference to the monitors it hands out, so this changes no monitor's interval
+        // and always returns `Ok`; only `InProcessMonitor::set_update_interval` changes it. Kept
+        // here so the `BitsClient` surface stays uniform across front ends.
+        Ok(())
+    }
+
+    // Likewise changes nothing and always returns `Ok`: an `InProcessMonitor` stops only when it
+    // is dropped.
+    pub fn stop_update(&mut self, _guid: Guid) -> Result<(), SetUpdateIntervalFailure> {
+        Ok(())
+    }
+
+    pub fn complete_job(&mut self, guid: Guid) -> Result<(), CompleteJobFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(CompleteJobFailure::NotFound)?;
+        job.complete()?;
+        Ok(())
+    }
+
+    pub fn cancel_job(&mut self, guid: Guid) -> Result<(), CancelJobFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(CancelJobFailure::NotFound)?;
+        job.cancel()?;
+        Ok(())
+    }
+
+    /// Read why job `guid` is in the `Error`/`TransientError` state, via
+    /// `IBackgroundCopyJob::GetError`.
+    pub fn get_job_error(&mut self, guid: Guid) -> Result<BitsJobFileError, GetJobErrorFailure> {
+        let job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(GetJobErrorFailure::NotFound)?;
+
+        let error = job.get_error()?.ok_or(GetJobErrorFailure::NoError)?;
+        let file_url = job.get_error_file()?;
+
+        Ok(BitsJobFileError { error, file_url })
+    }
+
+    /// Apply `options` to job `guid` via `SetMinimumRetryDelay`, `SetNoProgressTimeout`,
+    /// `SetNotifyFlags`, and `IBackgroundCopyJobHttpOptions`.
+    pub fn set_job_options(
+        &mut self,
+        guid: Guid,
+        options: JobOptions,
+    ) -> Result<(), SetJobOptionsFailure> {
+        let mut job = self
+            .manager
+            .find_job_by_guid(&guid)?
+            .ok_or(SetJobOptionsFailure::NotFound)?;
+
+        if let Some(secs) = options.minimum_retry_delay_secs {
+            job.set_minimum_retry_delay(secs)?;
+        }
+        if let Some(secs) = options.no_progress_timeout_secs {
+            job.set_no_progress_timeout(secs)?;
+        }
+        if !options.http_headers.is_empty() {
+            job.set_custom_http_headers(&options.http_headers)?;
+        }
+        if let Some(follow_redirects) = options.follow_redirects {
+            job.set_follow_redirects(follow_redirects)?;
+        }
+        if let Some(flags) = options.notify_flags {
+            job.set_notify_flags(flags)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Polls a single BITS job for status, boosting its priority to foreground while active.
+///
+/// The `IBackgroundCopyJob` pointer this wraps is tied to the COM apartment it was obtained on,
+/// so this deliberately isn't `Send`: a `BitsMonitorClient::InProcess(_)` that needs to move to
+/// another thread (e.g. `run_with_callbacks`'s monitor thread) has to be dropped and rebuilt
+/// there from its `Guid` via `reconnect`, rather than having its job pointer moved across
+/// threads directly.
+pub struct InProcessMonitor {
+    job: bits::BackgroundCopyJob,
+    guid: Guid,
+    interval_millis: u32,
+}
+
+impl InProcessMonitor {
+    fn new(mut job: bits::BackgroundCopyJob, guid: Guid, interval_millis: u32) -> InProcessMonitor {
+        let _ = job.set_priority(true);
+        InProcessMonitor {
+            job,
+            guid,
+            interval_millis,
+        }
+    }
+
+    pub fn guid(&self) -> Guid {
+        self.guid.clone()
+    }
+
+    /// Rebuild an equivalent monitor for the same job on whatever thread calls this, via a fresh
+    /// `BackgroundCopyManager` connection and `find_job_by_guid` lookup -- since the COM job
+    /// pointer a monitor already holds can't simply be moved to another thread.
+    pub fn reconnect(guid: Guid, interval_millis: u32) -> Result<InProcessMonitor, crate::Error> {
+        let job = BackgroundCopyManager::connect()?
+            .find_job_by_guid(&guid)?
+            .ok_or(crate::PipeError::NotConnected)?;
+        Ok(InProcessMonitor::new(job, guid, interval_millis))
+    }
+
+    pub fn get_status(&mut self, timeout_millis: u32) -> Result<BitsJobStatus, ComedyError> {
+        self.job.get_status(timeout_millis.min(self.interval_millis))
+    }
+
+    /// Read the status of each file in the job individually, via
+    /// `IBackgroundCopyJob::EnumFiles`/`IBackgroundCopyFile::GetProgress`.
+    pub fn get_files(&mut self) -> Result<Vec<BitsFileStatus>, ComedyError> {
+        self.job
+            .files()?
+            .map(|file| {
+                let file = file?;
+                let progress = file.progress()?;
+                Ok(BitsFileStatus {
+                    url: file.remote_name()?,
+                    local_name: file.local_name()?,
+                    bytes_total: progress.bytes_total,
+                    bytes_transferred: progress.bytes_transferred,
+                    completed: progress.completed,
+                })
+            })
+            .collect()
+    }
+
+    pub fn set_update_interval(&mut self, interval_millis: u32) {
+        self.interval_millis = interval_millis;
+    }
+}
+
+impl Drop for InProcessMonitor {
+    fn drop(&mut self) {
+        let _ = self.job.set_priority(false);
+    }
+}
@@ -0,0 +1,123 @@
+//! A small builder for assembling a multi-file BITS job, for callers who'd rather collect files
+//! (and any retry/HTTP options) one at a time than build the arguments for
+//! `BitsClient::start_job_with_files`/`set_job_options` themselves.
+
+use std::ffi::OsString;
+
+use failure::Fail;
+
+use crate::bits_protocol::{
+    BitsProxyUsage, JobOptions, SetJobOptionsFailure, StartJobFailure, StartJobSuccess,
+};
+use crate::{BitsClient, BitsMonitorClient, Error};
+
+/// Failure starting a job via `JobBuilder::start`: either `start_job_with_files` itself failed,
+/// or it succeeded but applying its `JobOptions` then failed with a Windows API error (also
+/// reported as `StartJob(StartJobFailure::Api(_))`), or the job was gone again (raced out from
+/// under us by something else entirely) before its `JobOptions` could be applied.
+#[derive(Debug, Fail)]
+pub enum JobBuilderStartFailure {
+    #[fail(display = "{}", _0)]
+    StartJob(#[fail(cause)] StartJobFailure),
+    #[fail(display = "job disappeared before its options could be applied")]
+    OptionsRace,
+}
+
+impl From<StartJobFailure> for JobBuilderStartFailure {
+    fn from(failure: StartJobFailure) -> JobBuilderStartFailure {
+        JobBuilderStartFailure::StartJob(failure)
+    }
+}
+
+/// Collects `(url, save_path)` pairs, and optionally a `JobOptions`, for a single BITS job
+/// before starting it.
+#[derive(Default)]
+pub struct JobBuilder {
+    files: Vec<(OsString, OsString)>,
+    options: JobOptions,
+}
+
+impl JobBuilder {
+    pub fn new() -> JobBuilder {
+        JobBuilder {
+            files: Vec::new(),
+            options: JobOptions::default(),
+        }
+    }
+
+    /// Add a file to download at `url`, saved to local path `save_path` (relative to the
+    /// `save_path_prefix` given when constructing the `BitsClient` this is eventually started
+    /// on).
+    pub fn add_file(mut self, url: OsString, save_path: OsString) -> JobBuilder {
+        self.files.push((url, save_path));
+        self
+    }
+
+    /// See `JobOptions::minimum_retry_delay_secs`.
+    pub fn minimum_retry_delay_secs(mut self, secs: u32) -> JobBuilder {
+        self.options.minimum_retry_delay_secs = Some(secs);
+        self
+    }
+
+    /// See `JobOptions::no_progress_timeout_secs`.
+    pub fn no_progress_timeout_secs(mut self, secs: u32) -> JobBuilder {
+        self.options.no_progress_timeout_secs = Some(secs);
+        self
+    }
+
+    /// Add one custom HTTP request header, sent with every request this job makes.
+    pub fn http_header(mut self, name: impl Into<String>, value: impl Into<String>) -> JobBuilder {
+        self.options.http_headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// See `JobOptions::follow_redirects`.
+    pub fn follow_redirects(mut self, follow_redirects: bool) -> JobBuilder {
+        self.options.follow_redirects = Some(follow_redirects);
+        self
+    }
+
+    /// See `JobOptions::notify_flags`.
+    pub fn notify_flags(mut self, flags: u32) -> JobBuilder {
+        self.options.notify_flags = Some(flags);
+        self
+    }
+
+    /// Start the job on `client`, then apply any options collected above. See
+    /// `BitsClient::start_job_with_files` for the meaning of `proxy_usage` and
+    /// `monitor_interval_millis`.
+    pub fn start(
+        self,
+        client: &mut BitsClient,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient), JobBuilderStartFailure>, Error> {
+        let started =
+            client.start_job_with_files(self.files, proxy_usage, monitor_interval_millis)?;
+
+        let (success, monitor) = match started {
+            Ok(started) => started,
+            Err(failure) => return Ok(Err(failure.into())),
+        };
+
+        if self.options != JobOptions::default() {
+            match client.set_job_options(success.guid.clone(), self.options)? {
+                Ok(()) => {}
+                Err(SetJobOptionsFailure::Api(err)) => {
+                    return Ok(Err(StartJobFailure::Api(err).into()))
+                }
+                // The job was just created by this same call, so `NotFound` would mean it
+                // vanished between `start_job_with_files` returning and this call going out --
+                // plausible for a `LocalService` client (a separate round trip to a helper
+                // process) only if something else cancelled the job out from under us. That's an
+                // external race, not a bug in this crate, so report it to the caller rather than
+                // panicking over it.
+                Err(SetJobOptionsFailure::NotFound) => {
+                    return Ok(Err(JobBuilderStartFailure::OptionsRace))
+                }
+            }
+        }
+
+        Ok(Ok((success, monitor)))
+    }
+}
@@ -1,15 +1,28 @@
+extern crate bincode;
 extern crate bits;
 extern crate comedy;
 extern crate failure;
 extern crate failure_derive;
 extern crate guid_win;
+extern crate serde_derive;
+extern crate winapi;
 
 pub mod bits_protocol;
 
+mod handle;
 mod in_process;
+mod job_builder;
+mod local_service;
+
+pub use handle::{BitsClientHandle, BitsMonitorHandle};
+pub use job_builder::{JobBuilder, JobBuilderStartFailure};
 
 use std::convert;
 use std::ffi;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 use bits_protocol::*;
 use failure::Fail;
@@ -19,8 +32,9 @@ pub use bits::{BitsJobError, BitsJobProgress, BitsJobStatus, BitsProxyUsage};
 pub use comedy::Error as ComedyError;
 pub use guid_win::Guid;
 
-// These errors would come from a Local Service client, this structure properly lives in the
-// crate that deals with named pipes.
+// These errors come from the `local_service` named-pipe client, from a `BitsClientHandle` or
+// `BitsMonitorHandle` whose worker thread has gone away (`NotConnected`), and from in-process
+// monitors, whose Windows API errors are reported as `Api`.
 #[derive(Clone, Debug, Eq, Fail, PartialEq)]
 pub enum PipeError {
     #[fail(display = "Pipe is not connected")]
@@ -39,13 +53,20 @@ impl convert::From<ComedyError> for PipeError {
     }
 }
 
+impl convert::From<io::Error> for PipeError {
+    fn from(err: io::Error) -> PipeError {
+        PipeError::Api(err.into())
+    }
+}
+
 pub use PipeError as Error;
 
 pub enum BitsClient {
     /// The InProcess variant does all BITS calls in-process.
     InProcess(in_process::InProcessClient),
-    // Space is reserved here for the LocalService variant, which will work through an external
-    // process running as Local Service.
+    /// The LocalService variant works through an external process running as Local Service,
+    /// reached over a named pipe.
+    LocalService(local_service::LocalServiceClient),
 }
 
 use BitsClient::*;
@@ -54,8 +75,9 @@ use BitsClient::*;
 ///
 /// Methods on `BitsClient` usually return a `Result<Result<_, xyzFailure>>`. The outer `Result`
 /// is `Err` if there was a communication error in sending the associated command or receiving
-/// its response. Currently this is always `Ok` as all clients are in-process. The inner
-/// `Result` is `Err` if there was an error executing the command.
+/// its response; for an `InProcess` client this is always `Ok`, but a `LocalService` client can
+/// fail to reach its helper process. The inner `Result` is `Err` if there was an error executing
+/// the command.
 impl BitsClient {
     /// Create an in-process `BitsClient`.
     /// `job_name` will be used when creating jobs, and this `BitsClient` can only be used to
@@ -71,6 +93,24 @@ impl BitsClient {
         )?))
     }
 
+    /// Create a `BitsClient` that talks to a helper process running as Local Service over a
+    /// named pipe, rather than making BITS calls in this process. Useful for callers that want
+    /// their jobs to outlive their own process, or that don't run with the permissions BITS
+    /// needs.
+    ///
+    /// `job_name` and `save_path_prefix` are accepted for symmetry with `new()`, but this client
+    /// does not pass them on to the helper process: each `save_path` given to `start_job` or
+    /// `start_job_with_files` is sent to the helper as given.
+    pub fn new_local_service(
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+    ) -> Result<BitsClient, Error> {
+        Ok(LocalService(local_service::LocalServiceClient::new(
+            job_name,
+            save_path_prefix,
+        )?))
+    }
+
     /// Start a job to download a single file at `url` to local path `save_path` (relative to the
     /// `save_path_prefix` given when constructing the `BitsClient`).
     ///
@@ -86,10 +126,33 @@ impl BitsClient {
         proxy_usage: BitsProxyUsage,
         monitor_interval_millis: u32,
     ) -> Result<Result<(StartJobSuccess, BitsMonitorClient), StartJobFailure>, Error> {
+        self.start_job_with_files(vec![(url, save_path)], proxy_usage, monitor_interval_millis)
+    }
+
+    /// Start a job downloading every `(url, save_path)` pair in `files` (each `save_path`
+    /// relative to the `save_path_prefix` given when constructing the `BitsClient`) as a single
+    /// BITS job: one id, one priority, one pause/resume, one retry policy, covering every file in
+    /// the set. See `JobBuilder` for a more ergonomic way to assemble `files`.
+    ///
+    /// `result.1`'s `get_status` reports aggregate progress (total bytes/files) across every file
+    /// in `files`; use `result.1.get_files()` for the status of each file individually.
+    pub fn start_job_with_files(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient), StartJobFailure>, Error> {
+        if files.is_empty() {
+            return Ok(Err(StartJobFailure::NoFiles));
+        }
+
         match self {
             InProcess(client) => Ok(client
-                .start_job(url, save_path, proxy_usage, monitor_interval_millis)
+                .start_job_with_files(files, proxy_usage, monitor_interval_millis)
                 .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            LocalService(client) => Ok(client
+                .start_job_with_files(files, proxy_usage, monitor_interval_millis)?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
         }
     }
 
@@ -104,6 +167,9 @@ impl BitsClient {
             InProcess(client) => Ok(client
                 .monitor_job(guid, interval_millis)
                 .map(|monitor| BitsMonitorClient::InProcess(monitor))),
+            LocalService(client) => Ok(client
+                .monitor_job(guid, interval_millis)?
+                .map(|monitor| BitsMonitorClient::LocalService(monitor))),
         }
     }
 
@@ -111,6 +177,7 @@ impl BitsClient {
     pub fn suspend_job(&mut self, guid: Guid) -> Result<Result<(), SuspendJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.suspend_job(guid)),
+            LocalService(client) => client.suspend_job(guid),
         }
     }
 
@@ -118,6 +185,7 @@ impl BitsClient {
     pub fn resume_job(&mut self, guid: Guid) -> Result<Result<(), ResumeJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.resume_job(guid)),
+            LocalService(client) => client.resume_job(guid),
         }
     }
 
@@ -137,6 +205,7 @@ impl BitsClient {
     ) -> Result<Result<(), SetJobPriorityFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.set_job_priority(guid, foreground)),
+            LocalService(client) => client.set_job_priority(guid, foreground),
         }
     }
 
@@ -148,6 +217,7 @@ impl BitsClient {
     ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.set_update_interval(guid, interval_millis)),
+            LocalService(client) => client.set_update_interval(guid, interval_millis),
         }
     }
 
@@ -158,6 +228,7 @@ impl BitsClient {
     ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.stop_update(guid)),
+            LocalService(client) => client.stop_update(guid),
         }
     }
 
@@ -167,6 +238,7 @@ impl BitsClient {
     pub fn complete_job(&mut self, guid: Guid) -> Result<Result<(), CompleteJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.complete_job(guid)),
+            LocalService(client) => client.complete_job(guid),
         }
     }
 
@@ -176,6 +248,36 @@ impl BitsClient {
     pub fn cancel_job(&mut self, guid: Guid) -> Result<Result<(), CancelJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.cancel_job(guid)),
+            LocalService(client) => client.cancel_job(guid),
+        }
+    }
+
+    /// Read why job `guid` is currently in the `Error`/`TransientError` state: the BITS call that
+    /// failed, the HRESULT it failed with, its localized description, and (if the error is
+    /// specific to one file in the job) that file's URL.
+    ///
+    /// Returns `GetJobErrorFailure::NoError` if the job isn't currently in an error state.
+    pub fn get_job_error(
+        &mut self,
+        guid: Guid,
+    ) -> Result<Result<BitsJobFileError, GetJobErrorFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client.get_job_error(guid)),
+            LocalService(client) => client.get_job_error(guid),
+        }
+    }
+
+    /// Apply retry/no-progress/HTTP options to job `guid`. Can be called any time after the job
+    /// is created; see `JobOptions` for what it covers. `JobBuilder` applies `JobOptions` set on
+    /// it this same way, right after starting the job.
+    pub fn set_job_options(
+        &mut self,
+        guid: Guid,
+        options: JobOptions,
+    ) -> Result<Result<(), SetJobOptionsFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client.set_job_options(guid, options)),
+            LocalService(client) => client.set_job_options(guid, options),
         }
     }
 }
@@ -183,6 +285,7 @@ impl BitsClient {
 /// A `BitsMonitorClient` is the client side of a monitor for a particular BITS job.
 pub enum BitsMonitorClient {
     InProcess(in_process::InProcessMonitor),
+    LocalService(local_service::LocalServiceMonitor),
 }
 
 impl BitsMonitorClient {
@@ -198,7 +301,274 @@ impl BitsMonitorClient {
     /// if it is stopped or dropped the priority will be returned to background, if possible.
     pub fn get_status(&mut self, timeout_millis: u32) -> Result<BitsJobStatus, Error> {
         match self {
-            BitsMonitorClient::InProcess(client) => client.get_status(timeout_millis),
+            BitsMonitorClient::InProcess(client) => {
+                client.get_status(timeout_millis).map_err(Error::from)
+            }
+            BitsMonitorClient::LocalService(client) => client.get_status(timeout_millis),
+        }
+    }
+
+    /// Read the status of each file in the job individually (url, local path, and its own
+    /// progress), for jobs with more than one file started via
+    /// `BitsClient::start_job_with_files`/`JobBuilder`.
+    pub fn get_files(&mut self) -> Result<Vec<BitsFileStatus>, Error> {
+        match self {
+            BitsMonitorClient::InProcess(client) => client.get_files().map_err(Error::from),
+            BitsMonitorClient::LocalService(client) => client.get_files(),
+        }
+    }
+
+    /// Spawn an internal loop that polls `get_status` approximately every `interval_millis`
+    /// milliseconds and invokes the relevant closure in `handlers` on each state transition,
+    /// instead of requiring the caller to reimplement that bookkeeping on top of `get_status`.
+    ///
+    /// `handlers.on_start` runs the first time the job is observed leaving `Queued`/`Connecting`.
+    /// `handlers.on_progress` runs whenever `BitsJobProgress` changes. `handlers.on_transferred`
+    /// runs once the job reaches `Transferred`. `handlers.on_error` runs whenever a
+    /// `BitsJobError` is present on the status, which can happen more than once (e.g. a
+    /// transient error clearing and recurring). `handlers.on_disconnected` runs exactly once, in
+    /// place of `on_transferred`/`on_error`, if the loop ever has to give up polling because the
+    /// connection to the job was lost (a failed `get_status`, or a `LocalService` helper process
+    /// that's gone away) rather than because the job reached a terminal state.
+    ///
+    /// Monitoring stops, and the job's priority is restored (the same as dropping a
+    /// `BitsMonitorClient` directly), either when the job reaches `Transferred` or `Error`, the
+    /// connection is lost, or the returned `MonitorGuard` is dropped.
+    pub fn run_with_callbacks(
+        self,
+        interval_millis: u32,
+        mut handlers: MonitorHandlers,
+    ) -> MonitorGuard {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        // `InProcessMonitor` wraps a COM job pointer tied to the apartment it was obtained on, so
+        // it can't simply be handed to the thread below; only its `Guid` crosses over, and an
+        // equivalent monitor is rebuilt from scratch on the new thread via
+        // `InProcessMonitor::reconnect`. `LocalServiceMonitor`'s pipe has no such affinity and can
+        // move across threads directly.
+        let origin = match self {
+            BitsMonitorClient::InProcess(monitor) => MonitorOrigin::InProcess(monitor.guid()),
+            BitsMonitorClient::LocalService(monitor) => MonitorOrigin::LocalService(monitor),
+        };
+
+        let thread = thread::spawn(move || {
+            let monitor = match origin {
+                MonitorOrigin::InProcess(guid) => {
+                    match in_process::InProcessMonitor::reconnect(guid, interval_millis) {
+                        Ok(monitor) => BitsMonitorClient::InProcess(monitor),
+                        Err(_) => {
+                            if let Some(on_disconnected) = handlers.on_disconnected.as_mut() {
+                                on_disconnected();
+                            }
+                            return;
+                        }
+                    }
+                }
+                MonitorOrigin::LocalService(monitor) => BitsMonitorClient::LocalService(monitor),
+            };
+
+            monitor_loop(monitor, interval_millis, handlers, thread_stop);
+        });
+
+        MonitorGuard {
+            stop,
+            thread: Some(thread),
         }
     }
 }
+
+/// What's needed to rebuild an equivalent `BitsMonitorClient` on another thread, without moving
+/// the original monitor itself: see `run_with_callbacks`.
+enum MonitorOrigin {
+    InProcess(Guid),
+    LocalService(local_service::LocalServiceMonitor),
+}
+
+fn monitor_loop(
+    mut monitor: BitsMonitorClient,
+    interval_millis: u32,
+    mut handlers: MonitorHandlers,
+    stop: Arc<AtomicBool>,
+) {
+    let mut started = false;
+    let mut last_progress: Option<BitsJobProgress> = None;
+
+    while !stop.load(Ordering::SeqCst) {
+        let status = match monitor.get_status(interval_millis) {
+            Ok(status) => status,
+            Err(_) => {
+                if let Some(on_disconnected) = handlers.on_disconnected.as_mut() {
+                    on_disconnected();
+                }
+                break;
+            }
+        };
+
+        let step = monitor_step(&mut started, status.state, status.error.is_some());
+
+        if step.fire_on_start {
+            if let Some(on_start) = handlers.on_start.as_mut() {
+                on_start();
+            }
+        }
+
+        if last_progress.as_ref() != Some(&status.progress) {
+            last_progress = Some(status.progress.clone());
+            if let Some(on_progress) = handlers.on_progress.as_mut() {
+                on_progress(status.progress.clone());
+            }
+        }
+
+        if step.fire_on_error {
+            if let Some(on_error) = handlers.on_error.as_mut() {
+                let error = status
+                    .error
+                    .clone()
+                    .expect("fire_on_error implies status.error.is_some()");
+                on_error(error);
+            }
+        }
+
+        if step.fire_on_transferred {
+            if let Some(on_transferred) = handlers.on_transferred.as_mut() {
+                on_transferred();
+            }
+        }
+
+        if step.stop {
+            break;
+        }
+    }
+}
+
+/// Whether `state` means the job has left the initial queued/connecting phase, mirroring the
+/// `transfer_started` helper that every downstream BITS monitor otherwise reimplements.
+fn transfer_started(state: BitsJobState) -> bool {
+    !matches!(state, BitsJobState::Queued | BitsJobState::Connecting)
+}
+
+/// Which of `MonitorHandlers`' callbacks should fire for one polled status, and whether
+/// `monitor_loop` should stop afterward. Split out from `monitor_loop` so this bookkeeping can be
+/// unit tested without a real `BitsMonitorClient`.
+struct MonitorStep {
+    fire_on_start: bool,
+    fire_on_error: bool,
+    fire_on_transferred: bool,
+    stop: bool,
+}
+
+fn monitor_step(started: &mut bool, state: BitsJobState, has_error: bool) -> MonitorStep {
+    let fire_on_start = !*started && transfer_started(state);
+    if fire_on_start {
+        *started = true;
+    }
+
+    let fire_on_transferred = matches!(state, BitsJobState::Transferred);
+    let stop = matches!(state, BitsJobState::Transferred | BitsJobState::Error);
+
+    MonitorStep {
+        fire_on_start,
+        fire_on_error: has_error,
+        fire_on_transferred,
+        stop,
+    }
+}
+
+type StartHandler = Box<dyn FnMut() + Send>;
+type ProgressHandler = Box<dyn FnMut(BitsJobProgress) + Send>;
+type TransferredHandler = Box<dyn FnMut() + Send>;
+type ErrorHandler = Box<dyn FnMut(BitsJobError) + Send>;
+type DisconnectedHandler = Box<dyn FnMut() + Send>;
+
+/// Closures invoked by `BitsMonitorClient::run_with_callbacks` on the state transitions callers
+/// otherwise have to notice for themselves by polling `get_status`. Any handler left `None` is
+/// simply not called.
+#[derive(Default)]
+pub struct MonitorHandlers {
+    pub on_start: Option<StartHandler>,
+    pub on_progress: Option<ProgressHandler>,
+    pub on_transferred: Option<TransferredHandler>,
+    pub on_error: Option<ErrorHandler>,
+    /// Runs exactly once, instead of `on_transferred`/`on_error`, if polling has to give up
+    /// because the connection to the job was lost rather than because the job finished.
+    pub on_disconnected: Option<DisconnectedHandler>,
+}
+
+/// Stops the monitoring loop started by `run_with_callbacks`, and restores the job's priority,
+/// when dropped (or when the job reaches `Transferred` or `Error` on its own).
+pub struct MonitorGuard {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for MonitorGuard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_started_is_false_while_queued_or_connecting() {
+        assert!(!transfer_started(BitsJobState::Queued));
+        assert!(!transfer_started(BitsJobState::Connecting));
+    }
+
+    #[test]
+    fn transfer_started_is_true_once_past_connecting() {
+        assert!(transfer_started(BitsJobState::Transferred));
+        assert!(transfer_started(BitsJobState::Error));
+    }
+
+    #[test]
+    fn monitor_step_fires_on_start_exactly_once() {
+        let mut started = false;
+
+        let first = monitor_step(&mut started, BitsJobState::Connecting, false);
+        assert!(!first.fire_on_start);
+        assert!(!started);
+
+        let second = monitor_step(&mut started, BitsJobState::Transferred, false);
+        assert!(second.fire_on_start);
+        assert!(started);
+
+        let third = monitor_step(&mut started, BitsJobState::Transferred, false);
+        assert!(!third.fire_on_start);
+    }
+
+    #[test]
+    fn monitor_step_fires_on_transferred_and_stops() {
+        let mut started = true;
+
+        let step = monitor_step(&mut started, BitsJobState::Transferred, false);
+        assert!(step.fire_on_transferred);
+        assert!(step.stop);
+        assert!(!step.fire_on_error);
+    }
+
+    #[test]
+    fn monitor_step_stops_on_error_without_firing_on_transferred() {
+        let mut started = true;
+
+        let step = monitor_step(&mut started, BitsJobState::Error, true);
+        assert!(step.fire_on_error);
+        assert!(step.stop);
+        assert!(!step.fire_on_transferred);
+    }
+
+    #[test]
+    fn monitor_step_can_fire_on_error_without_stopping() {
+        let mut started = true;
+
+        let step = monitor_step(&mut started, BitsJobState::Connecting, true);
+        assert!(step.fire_on_error);
+        assert!(!step.stop);
+    }
+}
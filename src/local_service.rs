@@ -0,0 +1,464 @@
+//! The `LocalService` `BitsClient` front end: talks to a helper process running as Local
+//! Service over a Windows named pipe, so that BITS jobs survive even if the calling process
+//! (which may be running with ordinary user privileges) exits.
+//!
+//! Every command is a length-prefixed, `bincode`-encoded `bits_protocol::Command`/`Response`
+//! pair: a `u32` little-endian byte count followed by the encoded body. Writes are issued with
+//! overlapped I/O so a per-call timeout can be enforced without blocking the pipe forever.
+
+use std::ffi;
+use std::io;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use winapi::shared::winerror::ERROR_IO_PENDING;
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::GetOverlappedResultEx;
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi::{CreateEventW, ResetEvent};
+use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
+use winapi::um::winnt::{GENERIC_READ, GENERIC_WRITE, HANDLE};
+
+use crate::bits_protocol::*;
+use crate::{Error, PipeError};
+
+const PIPE_NAME: &str = r"\\.\pipe\org.mozilla.bits_client";
+
+/// A connected handle to the Local Service helper process's command pipe.
+struct Pipe {
+    handle: HANDLE,
+    overlapped: Box<OVERLAPPED>,
+}
+
+// The HANDLE is owned exclusively by this struct and only ever touched behind `&mut self`.
+unsafe impl Send for Pipe {}
+
+impl Pipe {
+    fn connect(name: &str) -> Result<Pipe, PipeError> {
+        let wide_name: Vec<u16> = ffi::OsStr::new(name)
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        let handle = unsafe {
+            CreateFileW(
+                wide_name.as_ptr(),
+                GENERIC_READ | GENERIC_WRITE,
+                0,
+                ptr::null_mut(),
+                OPEN_EXISTING,
+                FILE_FLAG_OVERLAPPED,
+                ptr::null_mut(),
+            )
+        };
+
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(PipeError::NotConnected);
+        }
+
+        let event = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null_mut()) };
+        if event.is_null() {
+            unsafe { CloseHandle(handle) };
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event;
+
+        Ok(Pipe {
+            handle,
+            overlapped: Box::new(overlapped),
+        })
+    }
+
+    /// Write `body`, prefixed with its length, honoring `timeout`.
+    fn write_frame(&mut self, body: &[u8], timeout: Duration) -> Result<(), PipeError> {
+        let len = body.len() as u32;
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(body);
+
+        self.write_all(&frame, timeout)
+    }
+
+    fn write_all(&mut self, data: &[u8], timeout: Duration) -> Result<(), PipeError> {
+        use winapi::um::fileapi::WriteFile;
+
+        // The event is manual-reset and this same `overlapped` is reused for every call this
+        // `Pipe` ever makes, so it must be cleared before each operation: otherwise
+        // `GetOverlappedResultEx` below would see it still signaled from the *previous*
+        // completion and return immediately with stale data instead of waiting for this one.
+        unsafe { ResetEvent(self.overlapped.hEvent) };
+
+        let mut written: u32 = 0;
+        let ok = unsafe {
+            WriteFile(
+                self.handle,
+                data.as_ptr() as *const _,
+                data.len() as u32,
+                &mut written,
+                &mut *self.overlapped,
+            )
+        };
+
+        if ok == 0 && unsafe { winapi::um::errhandlingapi::GetLastError() } != ERROR_IO_PENDING {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut transferred: u32 = 0;
+        let ok = unsafe {
+            GetOverlappedResultEx(
+                self.handle,
+                &mut *self.overlapped,
+                &mut transferred,
+                timeout.as_millis() as u32,
+                0,
+            )
+        };
+
+        if ok == 0 {
+            return Err(PipeError::Timeout);
+        }
+
+        if transferred as usize != data.len() {
+            return Err(PipeError::WriteCount(data.len(), transferred));
+        }
+
+        Ok(())
+    }
+
+    fn read_frame(&mut self, timeout: Duration) -> Result<Vec<u8>, PipeError> {
+        let deadline = Instant::now() + timeout;
+
+        let mut len_bytes = [0u8; 4];
+        self.read_exact(&mut len_bytes, deadline)?;
+        let len = u32::from_le_bytes(len_bytes) as usize;
+
+        let mut body = vec![0u8; len];
+        self.read_exact(&mut body, deadline)?;
+        Ok(body)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], deadline: Instant) -> Result<(), PipeError> {
+        use winapi::um::fileapi::ReadFile;
+
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .ok_or(PipeError::Timeout)?;
+
+        // See the matching comment in `write_all`: the event must be reset before each reuse of
+        // `overlapped`, or this call can be satisfied by a stale signal from an earlier op.
+        unsafe { ResetEvent(self.overlapped.hEvent) };
+
+        let mut read: u32 = 0;
+        let ok = unsafe {
+            ReadFile(
+                self.handle,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as u32,
+                &mut read,
+                &mut *self.overlapped,
+            )
+        };
+
+        if ok == 0 && unsafe { winapi::um::errhandlingapi::GetLastError() } != ERROR_IO_PENDING {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut transferred: u32 = 0;
+        let ok = unsafe {
+            GetOverlappedResultEx(
+                self.handle,
+                &mut *self.overlapped,
+                &mut transferred,
+                remaining.as_millis() as u32,
+                0,
+            )
+        };
+
+        if ok == 0 || transferred as usize != buf.len() {
+            return Err(PipeError::Timeout);
+        }
+
+        Ok(())
+    }
+
+    fn call(&mut self, command: &Command, timeout: Duration) -> Result<Response, PipeError> {
+        let body = bincode::serialize(command).expect("Command always serializes");
+        self.write_frame(&body, timeout)?;
+        let reply = self.read_frame(timeout)?;
+        bincode::deserialize(&reply).map_err(|_| PipeError::NotConnected)
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.overlapped.hEvent);
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drives BITS jobs through a helper process running as Local Service.
+pub struct LocalServiceClient {
+    pipe: Pipe,
+}
+
+impl LocalServiceClient {
+    pub fn new(
+        _job_name: ffi::OsString,
+        _save_path_prefix: ffi::OsString,
+    ) -> Result<LocalServiceClient, Error> {
+        // The helper process is expected to already be running (started by the service control
+        // manager); if its pipe isn't there yet we report `NotConnected` rather than trying to
+        // launch it ourselves, since spawning a Local Service process is the installer's job.
+        Ok(LocalServiceClient {
+            pipe: Pipe::connect(PIPE_NAME)?,
+        })
+    }
+
+    pub fn start_job(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        self.start_job_with_files(vec![(url, save_path)], proxy_usage, monitor_interval_millis)
+    }
+
+    pub fn start_job_with_files(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        let command = Command::StartJob {
+            files,
+            proxy_usage,
+            monitor_interval_millis,
+        };
+
+        match self.pipe.call(&command, DEFAULT_TIMEOUT)? {
+            Response::StartJob(Ok(success)) => {
+                let monitor = LocalServiceMonitor {
+                    pipe: Pipe::connect(PIPE_NAME)?,
+                    guid: success.guid.clone(),
+                };
+                Ok(Ok((success, monitor)))
+            }
+            Response::StartJob(Err(StartJobCommandFailure::Api(msg))) => {
+                Ok(Err(StartJobFailure::Api(msg.into())))
+            }
+            _ => Err(PipeError::NotConnected),
+        }
+    }
+
+    pub fn monitor_job(
+        &mut self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<Result<LocalServiceMonitor, MonitorJobFailure>, Error> {
+        let command = Command::MonitorJob {
+            guid: guid.clone(),
+            interval_millis,
+        };
+
+        match self.pipe.call(&command, DEFAULT_TIMEOUT)? {
+            Response::MonitorJob(Ok(())) => Ok(Ok(LocalServiceMonitor {
+                pipe: Pipe::connect(PIPE_NAME)?,
+                guid,
+            })),
+            Response::MonitorJob(Err(failure)) => Ok(Err(failure.into())),
+            _ => Err(PipeError::NotConnected),
+        }
+    }
+
+    pub fn suspend_job(&mut self, guid: Guid) -> Result<Result<(), SuspendJobFailure>, Error> {
+        self.simple_call(Command::SuspendJob { guid }, |response| match response {
+            Response::SuspendJob(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn resume_job(&mut self, guid: Guid) -> Result<Result<(), ResumeJobFailure>, Error> {
+        self.simple_call(Command::ResumeJob { guid }, |response| match response {
+            Response::ResumeJob(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn set_job_priority(
+        &mut self,
+        guid: Guid,
+        foreground: bool,
+    ) -> Result<Result<(), SetJobPriorityFailure>, Error> {
+        self.simple_call(
+            Command::SetJobPriority { guid, foreground },
+            |response| match response {
+                Response::SetJobPriority(result) => Some(result),
+                _ => None,
+            },
+        )
+    }
+
+    pub fn set_update_interval(
+        &mut self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        self.simple_call(
+            Command::SetUpdateInterval {
+                guid,
+                interval_millis,
+            },
+            |response| match response {
+                Response::SetUpdateInterval(result) => Some(result),
+                _ => None,
+            },
+        )
+    }
+
+    pub fn stop_update(
+        &mut self,
+        guid: Guid,
+    ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        self.simple_call(Command::StopUpdate { guid }, |response| match response {
+            Response::StopUpdate(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn complete_job(&mut self, guid: Guid) -> Result<Result<(), CompleteJobFailure>, Error> {
+        self.simple_call(Command::CompleteJob { guid }, |response| match response {
+            Response::CompleteJob(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn cancel_job(&mut self, guid: Guid) -> Result<Result<(), CancelJobFailure>, Error> {
+        self.simple_call(Command::CancelJob { guid }, |response| match response {
+            Response::CancelJob(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn get_job_error(
+        &mut self,
+        guid: Guid,
+    ) -> Result<Result<BitsJobFileError, GetJobErrorFailure>, Error> {
+        match self.pipe.call(&Command::GetJobError { guid }, DEFAULT_TIMEOUT)? {
+            Response::GetJobError(Ok(detail)) => Ok(Ok(detail)),
+            Response::GetJobError(Err(GetJobErrorCommandFailure::NotFound)) => {
+                Ok(Err(GetJobErrorFailure::NotFound))
+            }
+            Response::GetJobError(Err(GetJobErrorCommandFailure::NoError)) => {
+                Ok(Err(GetJobErrorFailure::NoError))
+            }
+            Response::GetJobError(Err(GetJobErrorCommandFailure::Api(msg))) => {
+                Ok(Err(GetJobErrorFailure::Api(msg.into())))
+            }
+            _ => Err(PipeError::NotConnected),
+        }
+    }
+
+    pub fn set_job_options(
+        &mut self,
+        guid: Guid,
+        options: JobOptions,
+    ) -> Result<Result<(), SetJobOptionsFailure>, Error> {
+        self.simple_call(Command::SetJobOptions { guid, options }, |response| {
+            match response {
+                Response::SetJobOptions(result) => Some(result),
+                _ => None,
+            }
+        })
+    }
+
+    /// Send `command`, pull the matching variant out of the `Response` with `unwrap_variant`, and
+    /// translate the wire `CommandFailure` into the caller's own failure type.
+    fn simple_call<T, F>(
+        &mut self,
+        command: Command,
+        unwrap_variant: F,
+    ) -> Result<Result<(), T>, Error>
+    where
+        F: FnOnce(Response) -> Option<Result<(), CommandFailure>>,
+        T: From<CommandFailure>,
+    {
+        let response = self.pipe.call(&command, DEFAULT_TIMEOUT)?;
+        match unwrap_variant(response) {
+            Some(Ok(())) => Ok(Ok(())),
+            Some(Err(failure)) => Ok(Err(failure.into())),
+            None => Err(PipeError::NotConnected),
+        }
+    }
+}
+
+macro_rules! command_failure_into {
+    ($($failure:ident),* $(,)?) => {
+        $(
+            impl From<CommandFailure> for $failure {
+                fn from(failure: CommandFailure) -> $failure {
+                    match failure {
+                        CommandFailure::NotFound => $failure::NotFound,
+                        CommandFailure::Api(msg) => $failure::Api(msg.into()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+command_failure_into![
+    MonitorJobFailure,
+    SuspendJobFailure,
+    ResumeJobFailure,
+    SetJobPriorityFailure,
+    SetUpdateIntervalFailure,
+    CompleteJobFailure,
+    CancelJobFailure,
+    SetJobOptionsFailure,
+];
+
+/// Polls a single BITS job's status over its own monitor pipe connection.
+pub struct LocalServiceMonitor {
+    pipe: Pipe,
+    guid: Guid,
+}
+
+impl LocalServiceMonitor {
+    pub fn get_status(&mut self, timeout_millis: u32) -> Result<BitsJobStatus, Error> {
+        let command = Command::GetStatus {
+            guid: self.guid.clone(),
+            timeout_millis,
+        };
+
+        match self
+            .pipe
+            .call(&command, Duration::from_millis(timeout_millis as u64))?
+        {
+            Response::GetStatus(Ok(status)) => Ok(status),
+            Response::GetStatus(Err(_)) => Err(PipeError::NotConnected),
+            _ => Err(PipeError::NotConnected),
+        }
+    }
+
+    pub fn get_files(&mut self) -> Result<Vec<BitsFileStatus>, Error> {
+        let command = Command::GetFiles {
+            guid: self.guid.clone(),
+        };
+
+        match self.pipe.call(&command, DEFAULT_TIMEOUT)? {
+            Response::GetFiles(Ok(files)) => Ok(files),
+            Response::GetFiles(Err(_)) => Err(PipeError::NotConnected),
+            _ => Err(PipeError::NotConnected),
+        }
+    }
+}